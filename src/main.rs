@@ -5,21 +5,36 @@ use bevy::{
   prelude::*,
   render::{mesh::Indices, pipeline::PrimitiveTopology},
 };
+use bevy_prototype_debug_lines::{DebugLines, DebugLinesPlugin};
 
 const INDICES: [u16; 9] = [0, 1, 2, 3, 4, 5, 6, 7, 8];
 const POSITIONS: [f32; 6] = [0.0, 0.0, 5.0, -10.0, -5.0, -10.0];
 
 fn main() {
+  let window = WindowDescriptor {
+    height: 500.0,
+    width: 500.0,
+    ..Default::default()
+  };
+  // keep the boundary in lockstep with the window rather than re-typing extents
+  let boundary = Boundary::from_window(&window);
+
   App::build()
-    .insert_resource(WindowDescriptor {
-      height: 500.0,
-      width: 500.0,
-      ..Default::default()
-    })
+    .insert_resource(window)
     .insert_resource(ClearColor(Color::rgb(0.3, 0.2, 0.25)))
+    .insert_resource(SteeringWeights::default())
     .add_plugins(DefaultPlugins)
+    .add_plugin(DebugLinesPlugin)
+    .insert_resource(SpatialGrid::default())
     .add_startup_system(setup.system())
-    .add_system(boid_sense.system().chain(boid_control.system()))
+    .add_system(rebuild_grid.system().label("grid"))
+    .insert_resource(BoidLimits::default())
+    .insert_resource(boundary)
+    .add_system(boid_sense.system().chain(boid_control.system()).after("grid").label("control"))
+    .add_system(accumulate_forces.system().label("forces").after("control"))
+    .add_system(boundary_steering.system().after("forces").before("steer"))
+    .add_system(apply_steering.system().label("steer").after("forces"))
+    .add_system(draw_boid_gizmos.system())
     .add_system(velocity.system())
     .add_system(angular_velocity.system())
     // mouse shenanigans
@@ -49,25 +64,35 @@ fn setup(
 
   commands.spawn_bundle(OrthographicCameraBundle::new_2d());
   for i in 0..5 {
-    let i = i as f32;
-    commands
-      .spawn_bundle(SpriteBundle {
-        material: material_handle.clone(),
-        sprite: Sprite {
-          size: Vec2::splat(10.0),
-          ..Default::default()
-        },
-        transform: Transform::from_translation(Vec3::new(i * 10.0, i * 10.0, 0.0)),
-        mesh: mesh_handle.clone(),
+    let offset = i as f32;
+    let mut boid = commands.spawn_bundle(SpriteBundle {
+      material: material_handle.clone(),
+      sprite: Sprite {
+        size: Vec2::splat(10.0),
         ..Default::default()
-      })
+      },
+      transform: Transform::from_translation(Vec3::new(offset * 10.0, offset * 10.0, 0.0)),
+      mesh: mesh_handle.clone(),
+      ..Default::default()
+    });
+    boid
       .insert(Boid {
         neighbor_radius: 20.0,
         personal_radius: 2.0,
         coverage_angle: 1.5 * PI,
       })
       .insert(Velocity(100.0))
-      .insert(AngularVelocity(0.5 * PI));
+      .insert(AngularVelocity(0.5 * PI))
+      .insert(Acceleration(Vec2::ZERO))
+      .insert(CohesionForce(Vec2::ZERO))
+      .insert(SeparationForce(Vec2::ZERO))
+      .insert(AlignmentForce(Vec2::ZERO))
+      .insert(SteeringDebug::default());
+
+    // opt a single boid into the debug overlay rather than the whole flock
+    if i == 0 {
+      boid.insert(DebugBoid);
+    }
   }
 }
 
@@ -84,39 +109,205 @@ struct SensoryData
   too_close_map: HashMap<Entity, Vec<Entity>>,
 }
 
+// tunable blend coefficients for the three steering rules
+struct SteeringWeights
+{
+  cohesion: f32,
+  separation: f32,
+  alignment: f32,
+}
+
+impl Default for SteeringWeights
+{
+  fn default() -> Self
+  {
+    SteeringWeights
+    {
+      cohesion: 1.0,
+      separation: 1.0,
+      alignment: 1.0,
+    }
+  }
+}
+
+// accumulated desired acceleration for a boid this frame
+struct Acceleration(Vec2);
+
+// per-rule steering contributions, summed and clamped by `accumulate_forces`
+struct CohesionForce(Vec2);
+struct SeparationForce(Vec2);
+struct AlignmentForce(Vec2);
+
+// integration limits that keep the separated-acceleration model stable
+struct BoidLimits
+{
+  max_force: f32,
+  max_turn_rate: f32,
+  base_speed: f32,
+  speed_response: f32,
+}
+
+impl Default for BoidLimits
+{
+  fn default() -> Self
+  {
+    BoidLimits
+    {
+      max_force: 1.0,
+      max_turn_rate: 0.5 * PI,
+      base_speed: 100.0,
+      speed_response: 0.05,
+    }
+  }
+}
+
+// how the simulation treats a boid that reaches the edge of the window
+enum BoundaryMode
+{
+  // teleport the boid to the opposite edge, giving a toroidal world
+  Wrap,
+  // steer the boid back toward the center once it passes the margin
+  TurnBack,
+}
+
+// world-space extents the flock is kept within, derived from the window size
+struct Boundary
+{
+  min: Vec2,
+  max: Vec2,
+  mode: BoundaryMode,
+  margin: f32,
+}
+
+impl Boundary
+{
+  fn from_window(window: &WindowDescriptor) -> Self
+  {
+    let half = Vec2::new(window.width, window.height) / 2.0;
+    Boundary
+    {
+      min: -half,
+      max: half,
+      mode: BoundaryMode::TurnBack,
+      margin: 50.0,
+    }
+  }
+}
+
 // something that influences other boids but is not itself a boid
 struct PseudoBoid;
 
-fn boid_sense(boids: Query<(Entity, &Boid, &Transform)>, pseudo_boids: Query<Entity, With<(Transform, PseudoBoid)>>) -> SensoryData {
+// opt-in marker: boids carrying this get their perception radii and steering
+// vectors drawn by `draw_boid_gizmos`.
+struct DebugBoid;
+
+// the steering vectors `boid_control` computed this frame, stashed per boid so
+// the gizmo overlay can render them without recomputing.
+#[derive(Default)]
+struct SteeringDebug
+{
+  cohesion: Vec3,
+  separation: Vec3,
+}
+
+// broad-phase acceleration structure: buckets every boid (and pseudo-boid)
+// into square cells so a boid only has to look at its own cell plus the eight
+// neighboring ones instead of the whole world.
+struct SpatialGrid
+{
+  cell_size: f32,
+  cells: HashMap<(i32, i32), Vec<(Entity, Vec3)>>,
+}
+
+impl Default for SpatialGrid
+{
+  fn default() -> Self
+  {
+    SpatialGrid
+    {
+      cell_size: 1.0,
+      cells: HashMap::new(),
+    }
+  }
+}
+
+impl SpatialGrid
+{
+  fn cell_of(&self, translation: Vec3) -> (i32, i32)
+  {
+    (
+      (translation.x / self.cell_size).floor() as i32,
+      (translation.y / self.cell_size).floor() as i32,
+    )
+  }
+}
+
+// rebuilt every frame: pick a cell size wide enough to cover the largest
+// neighbor radius in the world, then drop every boid and pseudo-boid into the
+// cell containing its translation.
+fn rebuild_grid(
+  mut grid: ResMut<SpatialGrid>,
+  boids: Query<(Entity, &Boid, &Transform)>,
+  pseudo_boids: Query<(Entity, &Transform), With<PseudoBoid>>,
+) {
+  let cell_size = boids.iter()
+    .map(|(_, boid, _)| boid.neighbor_radius)
+    .fold(0.0_f32, f32::max);
+  // fall back to a sane positive size so cell coordinates never blow up
+  grid.cell_size = if cell_size > 0.0 { cell_size } else { 1.0 };
+
+  grid.cells.clear();
+  for (entity, _, transform) in boids.iter() {
+    let cell = grid.cell_of(transform.translation);
+    grid.cells.entry(cell).or_default().push((entity, transform.translation));
+  }
+  for (entity, transform) in pseudo_boids.iter() {
+    let cell = grid.cell_of(transform.translation);
+    grid.cells.entry(cell).or_default().push((entity, transform.translation));
+  }
+}
+
+fn boid_sense(boids: Query<(Entity, &Boid, &Transform)>, grid: Res<SpatialGrid>) -> SensoryData {
   // a map of vectors to track which boids can see which other boids
   let mut neighbor_map = HashMap::new();
   let mut too_close_map = HashMap::new();
   // outer loop picks one boid at a time to calculate sight
-  for (entity, boid, Transform { translation, .. }) in boids.iter() {
+  for (entity, boid, transform) in boids.iter() {
     let mut neighbor_boids = Vec::new();
     let mut too_close_boids = Vec::new();
 
-    // inner loop goes over all other boids and figures out which are seen
-    for (
-      other_entity,
-      _,
-      Transform {
-        translation: other_translation,
-        ..
-      },
-    ) in boids.iter()
-    {
-      // TODO add view angle, these boids are too powerful with 360° vision
-      let distance = translation.distance(*other_translation);
-      if distance <= boid.neighbor_radius {
-        neighbor_boids.push(other_entity);
-      }
-      if distance <= boid.personal_radius {
-        too_close_boids.push(other_entity);
+    let translation = transform.translation;
+    let forward = transform.rotation.mul_vec3(Vec3::Y).normalize();
+
+    // only the candidates in this boid's cell and the eight around it can be
+    // close enough to matter, so that is all we test.
+    let (cx, cy) = grid.cell_of(translation);
+    for dx in -1..=1 {
+      for dy in -1..=1 {
+        let candidates = match grid.cells.get(&(cx + dx, cy + dy)) {
+          Some(candidates) => candidates,
+          None => continue,
+        };
+
+        for (other_entity, other_translation) in candidates.iter() {
+          let distance = translation.distance(*other_translation);
+          if distance <= boid.neighbor_radius {
+            // a boid always perceives itself; everyone else has to fall inside
+            // the forward-facing vision wedge, leaving a blind spot behind.
+            let in_view = *other_entity == entity || {
+              let to_other = (*other_translation - translation).normalize();
+              forward.angle_between(to_other) <= boid.coverage_angle / 2.0
+            };
+            if in_view {
+              neighbor_boids.push(*other_entity);
+            }
+          }
+          // collision avoidance stays omnidirectional, so no FOV test here.
+          if distance <= boid.personal_radius {
+            too_close_boids.push(*other_entity);
+          }
+        }
       }
-
-      neighbor_boids.extend(pseudo_boids.iter());
-      too_close_boids.extend(pseudo_boids.iter());
     }
 
     neighbor_map.insert(entity, neighbor_boids);
@@ -133,29 +324,137 @@ fn boid_sense(boids: Query<(Entity, &Boid, &Transform)>, pseudo_boids: Query<Ent
 // noop function to debug problems and determine which function its happening
 // fn dump_chain(In(_): In<HashMap<Entity, Vec<Entity>>>) {}
 
+// each steering rule writes its desired direction into its own force component;
+// summing, clamping, and integration happen downstream in separate systems.
 fn boid_control(
   In(sensory_data): In<SensoryData>,
   boids: Query<Entity, (With<Transform>, With<Boid>)>,
-  transforms: Query<&Transform, With<Boid>>,
-  mut ang_velocities: Query<&mut AngularVelocity, With<Boid>>,
+  // resolves any sensed entity, including pseudo-boids that carry a transform
+  // but no `Boid`, so they can influence the flock without panicking.
+  transforms: Query<&Transform>,
+  mut forces: Query<(&mut CohesionForce, &mut SeparationForce, &mut AlignmentForce)>,
+  mut steering_debug: Query<&mut SteeringDebug>,
 ) {
   for entity in boids.iter() {
-    let neighbors = sensory_data.neighbor_map.get(&entity).unwrap()
+    let neighbors: Vec<&Transform> = sensory_data.neighbor_map.get(&entity).unwrap()
       .iter().map(|a| transforms.get(*a).unwrap()).collect();
     let too_close = sensory_data.too_close_map.get(&entity).unwrap()
       .iter().map(|a| transforms.get(*a).unwrap()).collect();
 
     let this = transforms.get(entity).unwrap();
-    let cohesion = cohesion(this, neighbors);
+    let cohesion = cohesion(this, neighbors.clone());
     let separation = separation(this, too_close);
+    let alignment = alignment(this, neighbors);
+
+    if let Ok((mut cohesion_force, mut separation_force, mut alignment_force)) = forces.get_mut(entity) {
+      cohesion_force.0 = cohesion.truncate();
+      separation_force.0 = separation.truncate();
+      alignment_force.0 = alignment.truncate();
+    }
+
+    // stash the raw direction vectors so the debug overlay can draw them
+    if let Ok(mut debug) = steering_debug.get_mut(entity) {
+      debug.cohesion = cohesion;
+      debug.separation = separation;
+    }
+  }
+}
+
+// sums the per-rule forces with their blend weights and clamps the result to a
+// maximum magnitude, producing the frame's desired acceleration.
+fn accumulate_forces(
+  mut query: Query<(&CohesionForce, &SeparationForce, &AlignmentForce, &mut Acceleration)>,
+  weights: Res<SteeringWeights>,
+  limits: Res<BoidLimits>,
+) {
+  for (cohesion, separation, alignment, mut acceleration) in query.iter_mut() {
+    let mut sum = weights.cohesion * cohesion.0
+      + weights.separation * separation.0
+      + weights.alignment * alignment.0;
+
+    if sum.length() > limits.max_force {
+      sum = sum.normalize() * limits.max_force;
+    }
+
+    acceleration.0 = sum;
+  }
+}
+
+// keeps the flock on screen: either wraps boids to the opposite edge, or adds a
+// center-seeking steering contribution that grows the further past the margin a
+// boid drifts.
+fn boundary_steering(
+  mut query: Query<(&mut Transform, &mut Acceleration)>,
+  boundary: Res<Boundary>,
+  limits: Res<BoidLimits>,
+) {
+  for (mut transform, mut acceleration) in query.iter_mut() {
+    match boundary.mode {
+      BoundaryMode::Wrap => {
+        let mut translation = transform.translation;
+        if translation.x < boundary.min.x {
+          translation.x = boundary.max.x;
+        } else if translation.x > boundary.max.x {
+          translation.x = boundary.min.x;
+        }
+        if translation.y < boundary.min.y {
+          translation.y = boundary.max.y;
+        } else if translation.y > boundary.max.y {
+          translation.y = boundary.min.y;
+        }
+        transform.translation = translation;
+      }
+      BoundaryMode::TurnBack => {
+        let position = transform.translation.truncate();
+        let inner_min = boundary.min + Vec2::splat(boundary.margin);
+        let inner_max = boundary.max - Vec2::splat(boundary.margin);
+
+        let mut push = Vec2::ZERO;
+        if position.x < inner_min.x {
+          push.x += inner_min.x - position.x;
+        } else if position.x > inner_max.x {
+          push.x -= position.x - inner_max.x;
+        }
+        if position.y < inner_min.y {
+          push.y += inner_min.y - position.y;
+        } else if position.y > inner_max.y {
+          push.y -= position.y - inner_max.y;
+        }
+
+        // normalize the overshoot by the margin so the push reaches full
+        // strength exactly as the boid crosses the edge.
+        acceleration.0 += push / boundary.margin;
+
+        // re-apply the max-force clamp `accumulate_forces` enforced, so the
+        // boundary push can redirect but not over-speed the boid.
+        if acceleration.0.length() > limits.max_force {
+          acceleration.0 = acceleration.0.normalize() * limits.max_force;
+        }
+      }
+    }
+  }
+}
 
-    let forward = this.rotation.mul_vec3(Vec3::Y).normalize();
-    let cohesion_angle = radians_to(forward, cohesion);
-    let separation_angle = radians_to(forward, separation);
-    let radians_delta = (cohesion_angle + separation_angle) / 2.0;
+// turns the desired acceleration into a bounded change in heading (never more
+// than `max_turn_rate`), and eases the scalar speed toward `base_speed` scaled
+// by the force magnitude relative to `max_force`.
+fn apply_steering(
+  mut query: Query<(&Acceleration, &Transform, &mut AngularVelocity, &mut Velocity)>,
+  limits: Res<BoidLimits>,
+) {
+  for (acceleration, transform, mut ang_vel, mut velocity) in query.iter_mut() {
+    if acceleration.0 == Vec2::ZERO {
+      ang_vel.0 = 0.0;
+      continue;
+    }
 
-    let mut ang_vel = ang_velocities.get_mut(entity).unwrap();
-    ang_vel.0 = radians_delta;
+    let forward = transform.rotation.mul_vec3(Vec3::Y).normalize();
+    let desired = radians_to(forward, acceleration.0.extend(0.0));
+    ang_vel.0 = desired.max(-limits.max_turn_rate).min(limits.max_turn_rate);
+
+    // a stronger steering force nudges the boid toward its cruising speed
+    let target = limits.base_speed * (acceleration.0.length() / limits.max_force);
+    velocity.0 += (target - velocity.0) * limits.speed_response;
   }
 }
 
@@ -223,6 +522,68 @@ fn separation(this: &Transform, boids: Vec<&Transform>) -> Vec3
   }
 }
 
+fn alignment(_this: &Transform, boids: Vec<&Transform>) -> Vec3
+{
+  let headings: Vec<Vec3> = boids.iter()
+    .map(|b| b.rotation.mul_vec3(Vec3::Y))
+    .collect();
+
+  let sum = headings.iter().sum::<Vec3>();
+  let count = Vec3::splat(headings.len() as f32);
+  let dir = (sum / count).normalize();
+
+  if dir.is_nan()
+  {
+    Vec3::ZERO
+  }
+  else
+  {
+    dir
+  }
+}
+
+// draws the perception radii, vision wedge, and steering vectors for every boid
+// tagged with `DebugBoid`, so turning decisions can be diagnosed visually. Bevy
+// 0.5 has no immediate-mode gizmos, so the overlay is drawn as one-frame debug
+// lines via `bevy_prototype_debug_lines`.
+fn draw_boid_gizmos(
+  mut lines: ResMut<DebugLines>,
+  query: Query<(&Boid, &Transform, &SteeringDebug), With<DebugBoid>>,
+) {
+  for (boid, transform, debug) in query.iter() {
+    let origin = transform.translation;
+    let forward = transform.rotation.mul_vec3(Vec3::Y).normalize();
+
+    // perception radii
+    draw_circle(&mut lines, origin, boid.neighbor_radius, Color::rgb(0.0, 1.0, 1.0));
+    draw_circle(&mut lines, origin, boid.personal_radius, Color::RED);
+
+    // vision wedge: two edges rotated +/- half the coverage angle off forward
+    let half = boid.coverage_angle / 2.0;
+    let left = Quat::from_rotation_z(half).mul_vec3(forward) * boid.neighbor_radius;
+    let right = Quat::from_rotation_z(-half).mul_vec3(forward) * boid.neighbor_radius;
+    lines.line_colored(origin, origin + left, 0.0, Color::YELLOW);
+    lines.line_colored(origin, origin + right, 0.0, Color::YELLOW);
+
+    // steering contributions, scaled up so short vectors are still visible
+    lines.line_colored(origin, origin + debug.cohesion * boid.neighbor_radius, 0.0, Color::GREEN);
+    lines.line_colored(origin, origin + debug.separation * boid.neighbor_radius, 0.0, Color::rgb(1.0, 0.5, 0.0));
+  }
+}
+
+// approximates a circle in the XY plane as a closed ring of short debug-line segments.
+fn draw_circle(lines: &mut DebugLines, center: Vec3, radius: f32, color: Color)
+{
+  const SEGMENTS: usize = 24;
+  let mut previous = center + Vec3::new(radius, 0.0, 0.0);
+  for step in 1..=SEGMENTS {
+    let theta = (step as f32 / SEGMENTS as f32) * 2.0 * PI;
+    let next = center + Vec3::new(radius * theta.cos(), radius * theta.sin(), 0.0);
+    lines.line_colored(previous, next, 0.0, color);
+    previous = next;
+  }
+}
+
 // ===PHYSICS===
 
 // units per second